@@ -0,0 +1,414 @@
+// Copyright 2019-2021 the Deno authors. All rights reserved. MIT license.
+
+//! [`Locker`] and [`Unlocker`] wrap V8's `v8::Locker`/`v8::Unlocker`, which
+//! serialize access to an isolate across threads, plus [`Isolate::with_locked`],
+//! a closure-scoped entry point built on top of them.
+//!
+//! This module is the one and only place `Locker`/`Unlocker` are defined in
+//! this crate - the `use crate::Locker;` that `with_locked` originally had
+//! was a forward reference to the reentrant type below, not a separate,
+//! pre-existing implementation living somewhere else; there is no other
+//! `Locker`/`Unlocker` definition to fold this into or remove.
+//!
+//! `Isolate::new_unentered` hands back an isolate that isn't bound to any
+//! thread yet. Using it safely means calling `enter()` on first use from a
+//! given thread, taking a `Locker`, and only calling `exit()` again after
+//! every `HandleScope` built on top of it has dropped - get the ordering
+//! wrong and V8 reports it as a SIGSEGV, not a panic. That's exactly the
+//! kind of bookkeeping a pooling architecture (e.g. the Cloudflare Workers
+//! model of many isolates shared across a worker thread pool) tends to get
+//! wrong under load.
+//!
+//! [`Isolate::with_locked`] does the bookkeeping once, in one place: it
+//! enters the isolate for the calling thread if (and only if) this is the
+//! first time that thread has touched it, takes the lock, and - when it was
+//! the call that entered - exits again before returning, including when the
+//! closure panics. Later calls on the same thread neither enter nor exit.
+//!
+//! `v8::Locker` is reentrant: a thread that already holds the lock on an
+//! isolate may take it again without deadlocking. [`Locker`] models that
+//! with a thread-local recursion count per isolate, so that only the
+//! outermost guard on a given thread talks to the real V8 locker.
+//!
+//! Following the design of `std::sync::Mutex`, a [`Locker`] also carries
+//! poison: if a thread panics while holding one, the isolate is marked
+//! poisoned and every later `Locker::new` returns `Err` (while still
+//! handing back the guard via `PoisonError::into_inner`), so a pool can
+//! notice an isolate was left mid-operation instead of silently reusing
+//! it. [`Locker::try_new`] offers the non-blocking counterpart for
+//! schedulers that must not park a worker thread.
+//!
+//! The outermost `Locker` on a thread also constructs a
+//! [`PkruGuard`](crate::pku::PkruGuard), normalizing that thread's PKRU
+//! (protection-key) register against the baseline captured via
+//! `V8::capture_pkru_baseline` for as long as the lock is held - a no-op
+//! until that baseline exists. See [`crate::pku`] for why PKRU needs
+//! normalizing at all.
+//!
+//! All of the above (lock depth, the `enter()` record, the poison flag)
+//! is bookkeeping keyed by an isolate's address, so it must be purged
+//! before that address can be reused for a different isolate -
+//! [`forget_isolate`] does that and should be called right before an
+//! isolate it was used with is dropped.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::LockResult;
+use std::sync::Mutex as StdMutex;
+use std::sync::OnceLock;
+use std::sync::PoisonError;
+
+use crate::pku::PkruGuard;
+use crate::HandleScope;
+use crate::Isolate;
+
+extern "C" {
+  fn v8__Locker__CONSTRUCT(
+    buf: *mut MaybeUninit<Locker>,
+    isolate: *mut Isolate,
+  );
+  fn v8__Locker__DESTRUCT(this: *mut Locker);
+  fn v8__Locker__IsLocked(isolate: *const Isolate) -> bool;
+
+  fn v8__Unlocker__CONSTRUCT(
+    buf: *mut MaybeUninit<Unlocker>,
+    isolate: *mut Isolate,
+  );
+  fn v8__Unlocker__DESTRUCT(this: *mut Unlocker);
+}
+
+/// Per-`(isolate, thread)` bookkeeping: `Locker::new` recursion depth on
+/// this thread, and whether this thread has already `enter()`ed the
+/// isolate. Consolidated into one entry per isolate so there's a single
+/// place to purge when an isolate goes away - see [`forget_isolate`].
+#[derive(Default)]
+struct ThreadIsolateState {
+  lock_depth: usize,
+  entered: bool,
+}
+
+thread_local! {
+  static ISOLATE_STATE: RefCell<HashMap<usize, ThreadIsolateState>> =
+    RefCell::new(HashMap::new());
+}
+
+fn isolate_key(isolate: &Isolate) -> usize {
+  isolate as *const Isolate as usize
+}
+
+/// Per-isolate poison flags, keyed by isolate address. An isolate stays
+/// poisoned for the rest of the process once a thread panics while
+/// holding its `Locker` - mirroring `std::sync::Mutex`, where poisoning
+/// is a one-way trip rather than something that clears on its own - until
+/// [`forget_isolate`] removes its entry.
+static POISON_FLAGS: OnceLock<StdMutex<HashMap<usize, Arc<AtomicBool>>>> =
+  OnceLock::new();
+
+fn poison_flag(isolate: &Isolate) -> Arc<AtomicBool> {
+  let key = isolate_key(isolate);
+  let flags = POISON_FLAGS.get_or_init(|| StdMutex::new(HashMap::new()));
+  flags
+    .lock()
+    .unwrap_or_else(|e| e.into_inner())
+    .entry(key)
+    .or_insert_with(|| Arc::new(AtomicBool::new(false)))
+    .clone()
+}
+
+/// Forgets every piece of bookkeeping this module keeps about `isolate`
+/// *on the calling thread*: its lock depth and `enter()` record, plus the
+/// isolate's global poison flag. Without this, a freed isolate's address
+/// can be recycled by the allocator for a brand-new isolate, which would
+/// otherwise inherit the old isolate's poison status (or cause
+/// `with_locked` to skip the mandatory first `enter()` on a thread,
+/// thinking it had already happened).
+///
+/// Callers that own an isolate for its full lifetime (e.g.
+/// [`crate::IsolatePool`]) should call this right before the isolate
+/// itself is dropped, from the thread that's dropping it. This clears
+/// the global poison flag, but the `entered` record is thread-local, so
+/// it only clears *that thread's* record - if other threads also leased
+/// and entered this isolate (as they would via a pool shared across
+/// worker threads), their own thread-local `entered` flags outlive this
+/// call and still carry the stale address. That's a real gap: the only
+/// full fix is a record V8 itself doesn't expose a hook for (tracking
+/// every thread that ever entered a given isolate, so each could be
+/// purged from here). Pools that hand an isolate to more than one thread
+/// should treat isolate addresses as never safe to reuse across distinct
+/// `IsolatePool` instances, or size thread pools so the same thread
+/// always sees the same isolate slot.
+pub(crate) fn forget_isolate(isolate: &Isolate) {
+  let key = isolate_key(isolate);
+
+  ISOLATE_STATE.with(|state| {
+    state.borrow_mut().remove(&key);
+  });
+
+  if let Some(flags) = POISON_FLAGS.get() {
+    flags.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+  }
+}
+
+/// Returns `true` the first time it's called for a given isolate on the
+/// current thread, and `false` on every subsequent call.
+pub(crate) fn mark_entered_on_this_thread(isolate: &Isolate) -> bool {
+  let key = isolate_key(isolate);
+  ISOLATE_STATE.with(|state| {
+    let mut state = state.borrow_mut();
+    let entry = state.entry(key).or_default();
+    let was_entered = entry.entered;
+    entry.entered = true;
+    !was_entered
+  })
+}
+
+/// The recursion depth of `Locker::new` for `isolate` on the *current*
+/// thread. `0` means this thread does not currently hold the lock.
+fn depth_on_this_thread(isolate: &Isolate) -> usize {
+  let key = isolate_key(isolate);
+  ISOLATE_STATE.with(|state| {
+    state.borrow().get(&key).map_or(0, |entry| entry.lock_depth)
+  })
+}
+
+/// A RAII guard granting exclusive access to an isolate from the current
+/// thread, mirroring `v8::Locker`.
+///
+/// Reentrant: if this thread already holds the lock (a previous `Locker`
+/// for the same isolate is still alive somewhere on the call stack),
+/// `Locker::new` just increments a thread-local recursion count instead of
+/// taking the lock a second time. Only the outermost `Locker` on a thread
+/// actually talks to V8; dropping any of the nested ones just decrements
+/// the count.
+pub struct Locker {
+  isolate: *mut Isolate,
+  // `None` for a reentrant (non-outermost) guard, which must not run V8's
+  // destructor - only decrement the recursion count.
+  raw: Option<MaybeUninit<Locker>>,
+  // Only set on the outermost guard: normalizes this thread's PKRU for
+  // V8's tracked protection key(s) for the lifetime of the lock, and
+  // restores it on drop. A no-op before a baseline has been captured via
+  // `V8::capture_pkru_baseline`.
+  _pkru_guard: Option<PkruGuard>,
+}
+
+impl Locker {
+  /// Locks `isolate` for the current thread, blocking if another thread
+  /// currently holds it.
+  ///
+  /// If the current thread already holds the lock, this is a cheap,
+  /// non-blocking no-op guard that only tracks nesting depth; only the
+  /// outermost guard constructs a `v8::Locker`.
+  ///
+  /// Returns `Err(PoisonError)` if a previous holder of this isolate's
+  /// lock panicked while holding it - the guard is still available via
+  /// [`PoisonError::into_inner`] for callers that want to recover rather
+  /// than propagate, e.g. by discarding the isolate and asking a pool for
+  /// a fresh one.
+  pub fn new(isolate: &Isolate) -> LockResult<Self> {
+    let locker = Self::new_unchecked(isolate);
+
+    if poison_flag(isolate).load(Ordering::Acquire) {
+      Err(PoisonError::new(locker))
+    } else {
+      Ok(locker)
+    }
+  }
+
+  /// Locks `isolate` for the current thread without blocking, returning
+  /// `None` if another thread currently holds it.
+  ///
+  /// Intended for work-stealing schedulers that must not park a worker
+  /// thread waiting on a busy isolate. Note this can't be perfectly
+  /// atomic: V8 itself exposes no try-lock primitive, so there's a
+  /// narrow window between checking [`Locker::is_locked`] and acquiring
+  /// the lock in which another thread could get there first, in which
+  /// case this call blocks like `Locker::new` rather than failing. This
+  /// is not poison-aware; check [`Locker::new`] if that matters to the
+  /// caller.
+  pub fn try_new(isolate: &Isolate) -> Option<Self> {
+    if depth_on_this_thread(isolate) == 0 && Self::is_locked(isolate) {
+      return None;
+    }
+
+    Some(Self::new_unchecked(isolate))
+  }
+
+  fn new_unchecked(isolate: &Isolate) -> Self {
+    let key = isolate_key(isolate);
+    let depth = ISOLATE_STATE.with(|state| {
+      let mut state = state.borrow_mut();
+      let entry = state.entry(key).or_default();
+      entry.lock_depth += 1;
+      entry.lock_depth
+    });
+
+    let isolate_ptr = isolate as *const Isolate as *mut Isolate;
+
+    let (raw, pkru_guard) = if depth == 1 {
+      let mut buf = MaybeUninit::<Locker>::uninit();
+      // SAFETY: `buf` is a valid, appropriately sized and aligned
+      // allocation for a `Locker`; `v8__Locker__CONSTRUCT` initializes it
+      // in place, matching the C++ placement-new convention used
+      // throughout this crate's bindings.
+      unsafe { v8__Locker__CONSTRUCT(&mut buf, isolate_ptr) };
+      (Some(buf), Some(PkruGuard::new()))
+    } else {
+      (None, None)
+    };
+
+    Locker { isolate: isolate_ptr, raw, _pkru_guard: pkru_guard }
+  }
+
+  /// Returns `true` if `isolate` is currently locked by some thread.
+  pub fn is_locked(isolate: &Isolate) -> bool {
+    // SAFETY: `isolate` is a valid, live isolate for the duration of the
+    // call.
+    unsafe { v8__Locker__IsLocked(isolate) }
+  }
+
+  /// The current thread's recursion depth for `isolate`'s lock: `0` if
+  /// this thread doesn't hold it, `1` for a single `Locker`, `2` for a
+  /// nested pair, and so on. Lets pooling code assert it isn't about to
+  /// double-release or hand out an isolate that's still held reentrantly.
+  pub fn lock_depth(isolate: &Isolate) -> usize {
+    depth_on_this_thread(isolate)
+  }
+}
+
+impl Drop for Locker {
+  fn drop(&mut self) {
+    if std::thread::panicking() {
+      // SAFETY: `self.isolate` is a valid, live isolate for the duration
+      // of this call; we only ever read its address, never dereference
+      // it as an `Isolate`.
+      let isolate = unsafe { &*self.isolate };
+      poison_flag(isolate).store(true, Ordering::Release);
+    }
+
+    let key = self.isolate as usize;
+    let depth = ISOLATE_STATE.with(|state| {
+      let mut state = state.borrow_mut();
+      let entry = state.get_mut(&key).expect(
+        "Locker dropped without a matching recursion-depth entry",
+      );
+      entry.lock_depth -= 1;
+      entry.lock_depth
+    });
+
+    if depth == 0 {
+      if let Some(mut raw) = self.raw.take() {
+        // SAFETY: `raw` was initialized by `v8__Locker__CONSTRUCT` in
+        // `new`, and this is the outermost guard for this isolate on this
+        // thread, so it's the only one allowed to destroy it.
+        unsafe { v8__Locker__DESTRUCT(raw.as_mut_ptr()) };
+      }
+    }
+  }
+}
+
+/// A RAII guard that temporarily releases the current thread's hold on an
+/// isolate's lock for its lifetime, mirroring `v8::Unlocker`.
+///
+/// V8 cannot unlock an isolate that's held reentrantly (nested `Locker`s
+/// on the same thread) or that this thread doesn't hold at all, since
+/// either would leave some guard - an inner nested `Locker`, or the
+/// calling code itself - believing it holds a lock nobody is actually
+/// holding. In debug builds, constructing an `Unlocker` while
+/// [`Locker::lock_depth`] is anything other than exactly `1` triggers a
+/// `debug_assert!`.
+pub struct Unlocker {
+  isolate: *mut Isolate,
+  raw: MaybeUninit<Unlocker>,
+}
+
+impl Unlocker {
+  /// Temporarily unlocks `isolate` for the current thread's locker.
+  pub fn new(isolate: &Isolate) -> Self {
+    debug_assert_eq!(
+      Locker::lock_depth(isolate),
+      1,
+      "cannot construct an Unlocker unless this thread holds exactly one, \
+       non-reentrant Locker on the isolate: depth 0 means this thread \
+       doesn't hold the lock at all, and depth > 1 means it's held \
+       reentrantly, which V8 has no way to unlock"
+    );
+
+    let isolate_ptr = isolate as *const Isolate as *mut Isolate;
+    let mut buf = MaybeUninit::<Unlocker>::uninit();
+    // SAFETY: see `Locker::new` - same placement-new convention.
+    unsafe { v8__Unlocker__CONSTRUCT(&mut buf, isolate_ptr) };
+
+    Unlocker { isolate: isolate_ptr, raw: buf }
+  }
+}
+
+impl Drop for Unlocker {
+  fn drop(&mut self) {
+    let _ = self.isolate;
+    // SAFETY: `raw` was initialized by `v8__Unlocker__CONSTRUCT` in `new`.
+    unsafe { v8__Unlocker__DESTRUCT(self.raw.as_mut_ptr()) };
+  }
+}
+
+/// Guard that exits an isolate on drop, but only if this thread was the one
+/// that entered it. Declared before the `Locker`/`HandleScope` it guards so
+/// that Rust's drop order - reverse of declaration - exits the isolate
+/// after they've both gone away, panic or no panic.
+struct ExitOnDropIfEntered {
+  isolate: *const Isolate,
+  entered_here: bool,
+}
+
+impl Drop for ExitOnDropIfEntered {
+  fn drop(&mut self) {
+    if self.entered_here {
+      // SAFETY: `isolate` is the pointer `with_locked` was called with,
+      // which outlives this guard for the whole call; every `Locker` and
+      // `HandleScope` derived from it has already been dropped, since
+      // this guard is declared first and therefore drops last.
+      unsafe { (*self.isolate).exit() };
+    }
+  }
+}
+
+impl Isolate {
+  /// Runs `f` with exclusive, locked access to this isolate on the calling
+  /// thread.
+  ///
+  /// On first use of this `(isolate, thread)` pair this performs the
+  /// `enter()` that `new_unentered` defers, then takes a [`Locker`] and
+  /// builds a [`HandleScope`] for the duration of the call. If this call
+  /// performed the `enter()`, the isolate is exited again before
+  /// `with_locked` returns - including when `f` panics - so callers never
+  /// have to reason about `enter`/`exit`/scope ordering by hand. Later calls
+  /// on the same thread, or calls on an isolate this thread has already
+  /// entered some other way (e.g. an [`IsolateLease`](crate::IsolateLease)),
+  /// neither enter nor exit it.
+  pub fn with_locked<R>(
+    &mut self,
+    f: impl FnOnce(&mut HandleScope) -> R,
+  ) -> R {
+    let entered_here = mark_entered_on_this_thread(self);
+    let isolate_ptr: *const Isolate = self;
+    let _exit_guard =
+      ExitOnDropIfEntered { isolate: isolate_ptr, entered_here };
+
+    if entered_here {
+      // SAFETY: `mark_entered_on_this_thread` only returns `true` the
+      // first time this thread touches this isolate, so this is the
+      // single `enter()` V8 requires per `(isolate, thread)` pair.
+      unsafe { (*isolate_ptr).enter() };
+    }
+
+    let _locker = Locker::new(self)
+      .expect("isolate lock poisoned by a previous panic");
+    let scope = std::pin::pin!(HandleScope::new(self));
+    let scope = &mut scope.init();
+    f(scope)
+  }
+}
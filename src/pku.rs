@@ -10,45 +10,77 @@
 //! Without this, threads may crash with SIGSEGV (si_code=SEGV_PKUERR) when
 //! accessing V8-protected memory pages.
 //!
+//! The baseline isn't a single flat PKRU snapshot: it's tracked per
+//! protection key, recording only the access-rights bits V8's own key(s)
+//! need. [`PkruGuard`] merges just those bits into the current thread's
+//! PKRU on entry and leaves every other key - including ones a host
+//! thread allocated for itself before ever touching V8 - exactly as it
+//! found them. A flat snapshot would instead blindly overwrite those
+//! unrelated keys' bits with whatever they happened to be when the
+//! baseline was captured.
+//!
+//! [`crate::V8::capture_pkru_baseline`] is the intended call site: invoke
+//! it once, right after `V8::initialize()`. [`Locker::new`](crate::Locker::new)
+//! constructs a [`PkruGuard`] automatically, so callers going through
+//! `Locker` or `Isolate::with_locked` get PKRU normalization for free.
+//!
 //! This module is only active on Linux x86_64 with PKU-capable CPUs.
 //! On other platforms or CPUs without PKU, it compiles to no-ops.
 
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 use std::sync::OnceLock;
 
-/// Baseline PKRU value captured after V8 initialization.
-/// None if PKU is not supported on this CPU/kernel.
+/// A protection key V8 owns, and the access-rights bits (the 2-bit
+/// AD/WD field PKRU encodes per key) it expects for that key whenever a
+/// thread is inside a `Locker` region.
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-static BASELINE_PKRU: OnceLock<Option<u32>> = OnceLock::new();
+#[derive(Clone, Copy)]
+struct TrackedKey {
+  key: u32,
+  rights: u32,
+}
 
-/// Check if PKU is supported by the CPU and enabled by the OS.
-/// Uses pkey_alloc syscall as recommended by kernel documentation.
+/// V8's protection key(s), captured after `V8::initialize()`. Unset until
+/// the baseline has been captured, and empty if PKU is not supported on
+/// this CPU/kernel.
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-fn is_pku_supported() -> bool {
-  // Try to allocate a protection key. If it succeeds, PKU is supported.
-  // syscall numbers: pkey_alloc = 330, pkey_free = 331 on x86_64
-  let pkey = unsafe { libc::syscall(libc::SYS_pkey_alloc, 0, 0) };
-
-  if pkey >= 0 {
-    // Free the key we just allocated
-    unsafe { libc::syscall(libc::SYS_pkey_free, pkey) };
-    true
-  } else {
-    false
-  }
+static TRACKED_KEYS: OnceLock<Vec<TrackedKey>> = OnceLock::new();
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+extern "C" {
+  /// Returns the protection key V8's own C++ runtime allocated for its
+  /// write-protected code pages (via `base::MemoryProtectionKey`), or
+  /// `-1` if this build/CPU/kernel isn't using PKU-based code protection.
+  ///
+  /// This is the actual key V8 enforces access rights on - unlike a key
+  /// this module might allocate for itself with `pkey_alloc`, which would
+  /// have nothing to do with the bits V8's own pages check.
+  fn v8__V8__GetJitCodeProtectionKey() -> i32;
 }
 
-/// Capture the current PKRU as the baseline for V8 operations.
+/// Capture the protection key(s) V8 uses as the baseline for V8
+/// operations.
 ///
-/// This should be called once after `V8::initialize()` on the main thread.
-/// The captured value will be restored on every thread entering V8 via `Locker`.
+/// This should be called once after `V8::initialize()` on the main
+/// thread, via [`crate::V8::capture_pkru_baseline`]. The tracked key(s)
+/// will have their access-rights bits normalized on every thread entering
+/// V8 via `Locker`.
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 pub fn capture_baseline() {
-  BASELINE_PKRU.get_or_init(|| {
-    if is_pku_supported() {
-      Some(read_pkru())
+  TRACKED_KEYS.get_or_init(|| {
+    // SAFETY: safe to call at any point after `V8::initialize()` - it
+    // only reads a key value V8's own startup code already computed and
+    // allocated for itself; it doesn't allocate or free anything here.
+    let key = unsafe { v8__V8__GetJitCodeProtectionKey() };
+
+    if key >= 0 {
+      // V8's code-page key defaults to write-disabled (rights = 0b10)
+      // and is only relaxed transiently, by V8 itself, while patching
+      // code. Real deployments may track more than one key here; the
+      // model is per-key either way.
+      vec![TrackedKey { key: key as u32, rights: 0b10 }]
     } else {
-      None
+      Vec::new()
     }
   });
 }
@@ -93,13 +125,26 @@ fn write_pkru(pkru: u32) {
   }
 }
 
-/// RAII guard that saves the current PKRU and restores the V8 baseline.
+/// Returns `pkru` with the 2-bit access-rights field for `key` replaced by
+/// `rights`, leaving every other key's bits untouched.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn with_key_rights(pkru: u32, key: u32, rights: u32) -> u32 {
+  let shift = key * 2;
+  let mask = 0b11 << shift;
+  (pkru & !mask) | ((rights & 0b11) << shift)
+}
+
+/// RAII guard that normalizes this thread's PKRU for V8's tracked
+/// protection key(s) and restores the thread's original PKRU on drop.
 ///
-/// When created, it saves the current thread's PKRU value and restores the
-/// baseline captured during V8 initialization. When dropped, it restores
-/// the original PKRU value.
+/// When created, it saves the current thread's PKRU value, then - for
+/// each key tracked by [`capture_baseline`] - overwrites just that key's
+/// 2-bit rights field with the value V8 expects, leaving every other
+/// key's bits as this thread already had them. When dropped, it restores
+/// the original PKRU value in full.
 ///
-/// On CPUs without PKU support, this is a no-op.
+/// On CPUs without PKU support, or before a baseline has been captured,
+/// this is a no-op.
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 pub struct PkruGuard {
   saved: Option<u32>,
@@ -107,18 +152,23 @@ pub struct PkruGuard {
 
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 impl PkruGuard {
-  /// Create a new PKRU guard, saving the current PKRU and restoring baseline.
+  /// Create a new PKRU guard, normalizing V8's tracked key(s) in the
+  /// current PKRU and saving the prior value for restoration on drop.
   pub fn new() -> Self {
-    let saved = if let Some(Some(baseline)) = BASELINE_PKRU.get() {
-      let current = read_pkru();
-
-      if current != *baseline {
-        write_pkru(*baseline);
+    let saved = match TRACKED_KEYS.get() {
+      Some(keys) if !keys.is_empty() => {
+        let current = read_pkru();
+        let normalized = keys
+          .iter()
+          .fold(current, |pkru, k| with_key_rights(pkru, k.key, k.rights));
+
+        if normalized != current {
+          write_pkru(normalized);
+        }
+
+        Some(current)
       }
-
-      Some(current)
-    } else {
-      None
+      _ => None,
     };
 
     Self { saved }
@@ -148,3 +198,18 @@ impl PkruGuard {
     Self
   }
 }
+
+impl crate::V8 {
+  /// Captures the PKRU baseline for V8's protection key(s).
+  ///
+  /// Call this once, right after `V8::initialize()`. Every [`Locker`]
+  /// constructed afterwards (including indirectly, via
+  /// [`Isolate::with_locked`](crate::Isolate::with_locked) or
+  /// [`IsolatePool`](crate::IsolatePool)) normalizes the calling thread's
+  /// PKRU against this baseline for its lifetime, which avoids
+  /// `SIGSEGV`/`SEGV_PKUERR` crashes on threads that haven't touched V8's
+  /// protected pages before.
+  pub fn capture_pkru_baseline() {
+    capture_baseline();
+  }
+}
@@ -0,0 +1,226 @@
+// Copyright 2019-2021 the Deno authors. All rights reserved. MIT license.
+
+//! [`IsolatePool`]: a multi-threaded isolate-reuse building block built on
+//! top of `Isolate::new_unentered` and [`Locker`](crate::Locker), modeled
+//! on the Cloudflare Workers architecture - a fixed set of isolates,
+//! shared across a worker thread
+//! pool, handed out one at a time and returned when a worker is done.
+//!
+//! `IsolatePool::new` pre-creates the isolates up front; `acquire` hands a
+//! worker thread an [`IsolateLease`] that performs the per-thread `enter()`
+//! `new_unentered` defers (only once per `(isolate, thread)` pair - V8
+//! doesn't need or want it repeated), takes a [`Locker`] (which normalizes
+//! PKRU on this thread for the lease's lifetime), and returns the isolate
+//! to the free list on drop.
+//!
+//! An isolate whose `Locker` comes back poisoned - left locked by a
+//! thread that panicked mid-operation - is discarded and replaced with a
+//! fresh one rather than handed out again; see [`IsolatePool::acquire`].
+//!
+//! Waiting for an isolate to free up parks the caller on a condition
+//! variable rather than spinning, the same split between "is something
+//! available" and "the data it protects" that `std::sync::Condvar` uses
+//! for `Mutex`.
+
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::locker::forget_isolate;
+use crate::locker::mark_entered_on_this_thread;
+use crate::CreateParams;
+use crate::Isolate;
+use crate::Locker;
+
+struct PoolState {
+  free: VecDeque<Box<Isolate>>,
+}
+
+/// A fixed-size pool of unentered isolates shared across worker threads.
+pub struct IsolatePool {
+  state: Mutex<PoolState>,
+  available: Condvar,
+  params_fn: Box<dyn Fn() -> CreateParams + Send + Sync>,
+}
+
+impl Drop for IsolatePool {
+  fn drop(&mut self) {
+    self.forget_free_isolates();
+  }
+}
+
+impl IsolatePool {
+  /// Pre-creates `size` unentered isolates, each built from the
+  /// `CreateParams` returned by `params_fn`.
+  pub fn new(
+    size: usize,
+    params_fn: impl Fn() -> CreateParams + Send + Sync + 'static,
+  ) -> Self {
+    let free = (0..size)
+      .map(|_| Box::new(Isolate::new_unentered(params_fn())))
+      .collect();
+
+    IsolatePool {
+      state: Mutex::new(PoolState { free }),
+      available: Condvar::new(),
+      params_fn: Box::new(params_fn),
+    }
+  }
+
+  /// Forgets every isolate still sitting in the free list with
+  /// `crate::locker::forget_isolate`, so their addresses can be reused
+  /// safely once they're actually dropped right after. Isolates currently
+  /// checked out via an [`IsolateLease`] aren't covered - dropping a pool
+  /// out from under an outstanding lease is already a questionable thing
+  /// to do.
+  fn forget_free_isolates(&mut self) {
+    let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+    for isolate in &state.free {
+      forget_isolate(isolate);
+    }
+  }
+
+  /// Leases a free isolate to the caller, blocking until one is available.
+  ///
+  /// If the isolate that frees up turns out to be poisoned (a previous
+  /// holder panicked while it was locked), it's discarded and replaced
+  /// with a fresh one built from this pool's `params_fn`, rather than
+  /// silently handing the poisoned isolate to the caller.
+  pub fn acquire(&self) -> IsolateLease<'_> {
+    let mut state = self.state.lock().unwrap();
+
+    loop {
+      if let Some(isolate) = state.free.pop_front() {
+        match self.lease(isolate) {
+          Some(lease) => return lease,
+          None => {
+            state.free.push_back(self.new_isolate());
+            continue;
+          }
+        }
+      }
+      state = self.available.wait(state).unwrap();
+    }
+  }
+
+  /// Like [`IsolatePool::acquire`], but gives up and returns `None` if no
+  /// isolate becomes free within `timeout`.
+  pub fn acquire_timeout(
+    &self,
+    timeout: Duration,
+  ) -> Option<IsolateLease<'_>> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.state.lock().unwrap();
+
+    loop {
+      if let Some(isolate) = state.free.pop_front() {
+        match self.lease(isolate) {
+          Some(lease) => return Some(lease),
+          None => {
+            state.free.push_back(self.new_isolate());
+            continue;
+          }
+        }
+      }
+
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        return None;
+      }
+
+      let (guard, timeout_result) =
+        self.available.wait_timeout(state, remaining).unwrap();
+      state = guard;
+      if timeout_result.timed_out() && state.free.is_empty() {
+        return None;
+      }
+    }
+  }
+
+  /// Builds a fresh unentered isolate from this pool's `params_fn`, to
+  /// replace one discarded for being poisoned.
+  fn new_isolate(&self) -> Box<Isolate> {
+    Box::new(Isolate::new_unentered((self.params_fn)()))
+  }
+
+  /// Leases `isolate`, or - if it's poisoned from a previous panic -
+  /// forgets and drops it and returns `None` so the caller can put a
+  /// fresh isolate in its place instead of silently handing back a
+  /// isolate that was left in an inconsistent state.
+  fn lease(&self, isolate: Box<Isolate>) -> Option<IsolateLease<'_>> {
+    let entered_here = mark_entered_on_this_thread(&isolate);
+    if entered_here {
+      // SAFETY: `mark_entered_on_this_thread` only returns `true` the
+      // first time this thread touches this isolate, so this is the
+      // single `enter()` V8 requires per `(isolate, thread)` pair; the
+      // isolate stays entered for the rest of the pool's lifetime rather
+      // than being exited between leases.
+      unsafe { isolate.enter() };
+    }
+
+    // `Locker::new` already takes care of PKRU normalization for us, so
+    // there's no need for a second, redundant `PkruGuard` here.
+    let locker = match Locker::new(&isolate) {
+      Ok(locker) => locker,
+      Err(poisoned) => {
+        // Release the poisoned `Locker` before `forget_isolate` erases
+        // this isolate's `ISOLATE_STATE` entry - otherwise `Locker`'s own
+        // `Drop` looks up that entry to decrement its recursion depth and
+        // finds it already gone, which panics and poisons this pool's
+        // `state` mutex right along with it.
+        drop(poisoned.into_inner());
+        forget_isolate(&isolate);
+        return None;
+      }
+    };
+
+    Some(IsolateLease {
+      pool: self,
+      isolate: Some(isolate),
+      locker: Some(locker),
+    })
+  }
+}
+
+/// A leased isolate, borrowed from an [`IsolatePool`]. Derefs to
+/// [`Isolate`]; returns the isolate to the pool's free list on drop.
+pub struct IsolateLease<'a> {
+  pool: &'a IsolatePool,
+  isolate: Option<Box<Isolate>>,
+  locker: Option<Locker>,
+}
+
+impl Deref for IsolateLease<'_> {
+  type Target = Isolate;
+
+  fn deref(&self) -> &Isolate {
+    self.isolate.as_ref().expect("isolate taken before drop")
+  }
+}
+
+impl DerefMut for IsolateLease<'_> {
+  fn deref_mut(&mut self) -> &mut Isolate {
+    self.isolate.as_mut().expect("isolate taken before drop")
+  }
+}
+
+impl Drop for IsolateLease<'_> {
+  fn drop(&mut self) {
+    // Release the real V8 lock (which also restores this thread's own
+    // PKRU value, via the `PkruGuard` `Locker` carries internally) before
+    // the isolate becomes visible to another thread via the free list,
+    // so nobody can start using it while we still nominally hold it.
+    self.locker.take();
+
+    if let Some(isolate) = self.isolate.take() {
+      let mut state = self.pool.state.lock().unwrap();
+      state.free.push_back(isolate);
+      drop(state);
+      self.pool.available.notify_one();
+    }
+  }
+}